@@ -5,11 +5,63 @@ use csv::Reader;
 use csv::Writer;
 use futures::future::join_all;
 use regex::Regex;
-use reqwest::Client;
+use rand::Rng;
+use reqwest::{header::CONTENT_RANGE, Client, StatusCode};
 use std::fs::{create_dir_all, File};
-use std::{collections::HashSet, fs};
+use std::sync::Arc;
+use std::time::Duration;
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    io::Read,
+};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{Mutex, OwnedSemaphorePermit, Semaphore};
 
-async fn fetch_full_filename(client: &Client, accession: &str) -> Result<(String, String)> {
+/// Caps concurrent requests to each download host independently, so a large
+/// `--max-concurrent` doesn't let us exceed what any single server tolerates.
+struct HostLimiter {
+    per_host_limit: usize,
+    hosts: Mutex<HashMap<String, Arc<Semaphore>>>,
+}
+
+impl HostLimiter {
+    fn new(per_host_limit: usize) -> Self {
+        Self {
+            per_host_limit,
+            hosts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Acquire a permit for the host parsed out of `url`, holding it for as
+    /// long as the returned permit is alive.
+    async fn acquire(&self, url: &str) -> Result<OwnedSemaphorePermit> {
+        let host = reqwest::Url::parse(url)
+            .context("Failed to parse URL for host throttling")?
+            .host_str()
+            .ok_or_else(|| anyhow!("URL has no host: {}", url))?
+            .to_string();
+
+        let semaphore = {
+            let mut hosts = self.hosts.lock().await;
+            hosts
+                .entry(host)
+                .or_insert_with(|| Arc::new(Semaphore::new(self.per_host_limit)))
+                .clone()
+        };
+
+        semaphore
+            .acquire_owned()
+            .await
+            .context("Host semaphore was unexpectedly closed")
+    }
+}
+
+async fn fetch_full_filename(
+    client: &Client,
+    accession: &str,
+    host_limiter: &HostLimiter,
+) -> Result<(String, String)> {
     let (db, acc) = accession
         .trim()
         .split_once('_')
@@ -27,6 +79,7 @@ async fn fetch_full_filename(client: &Client, accession: &str) -> Result<(String
         "https://ftp.ncbi.nlm.nih.gov/genomes/all/{}/{}",
         db, number_path
     );
+    let _host_permit = host_limiter.acquire(&base_url).await?;
     let directory_response = client.get(&base_url).send().await?;
     if !directory_response.status().is_success() {
         return Err(anyhow!(
@@ -61,27 +114,202 @@ async fn fetch_full_filename(client: &Client, accession: &str) -> Result<(String
     ))
 }
 
+/// Parse an NCBI `md5checksums.txt` manifest into a map of `<filename>` -> md5 hex digest,
+/// with the manifest's leading `./` stripped from each filename.
+fn parse_checksums(path: &PathBuf) -> Result<HashMap<String, String>> {
+    let contents = fs::read_to_string(path).context("Failed to read checksums manifest")?;
+    let mut checksums = HashMap::new();
+    for line in contents.lines() {
+        let mut parts = line.split_whitespace();
+        let hash = parts.next();
+        let name = parts.next();
+        if let (Some(hash), Some(name)) = (hash, name) {
+            checksums.insert(name.trim_start_matches("./").to_string(), hash.to_string());
+        }
+    }
+    Ok(checksums)
+}
+
+/// Compute the MD5 digest of a file on disk, returned as a lowercase hex string.
+///
+/// Hashes incrementally over a buffered reader, and runs on a blocking task,
+/// so verifying a multi-hundred-MB genome file neither spikes memory nor
+/// stalls the other concurrent downloads on the async runtime.
+async fn md5_hex(path: &PathBuf) -> Result<String> {
+    let path = path.clone();
+    tokio::task::spawn_blocking(move || {
+        let file =
+            std::fs::File::open(&path).context("Failed to open file for checksum verification")?;
+        let mut reader = std::io::BufReader::new(file);
+        let mut context = md5::Context::new();
+        let mut buf = [0u8; 64 * 1024];
+        loop {
+            let read = reader
+                .read(&mut buf)
+                .context("Failed to read file for checksum verification")?;
+            if read == 0 {
+                break;
+            }
+            context.consume(&buf[..read]);
+        }
+        Ok(format!("{:x}", context.compute()))
+    })
+    .await
+    .context("Checksum hashing task panicked")?
+}
+
+/// Sleep for an exponentially increasing delay (plus jitter) before the next retry,
+/// so we back off gracefully instead of hammering a server that's returning errors.
+async fn backoff_sleep(attempt: u32) {
+    let base = Duration::from_millis(500);
+    let exp_delay = base.saturating_mul(1 << attempt.min(6));
+    let jitter = Duration::from_millis(rand::thread_rng().gen_range(0..250));
+    tokio::time::sleep(exp_delay + jitter).await;
+}
+
 async fn download_with_retry(
     client: &Client,
     url: &str,
     file_name: PathBuf,
     retry_count: u32,
+    expected_md5: Option<&str>,
+    host_limiter: &HostLimiter,
 ) -> Result<()> {
+    let part_file = PathBuf::from(format!("{}.part", file_name));
     let mut attempts = retry_count;
     while attempts > 0 {
-        let response = client.get(url).send().await;
-        match response {
-            Ok(resp) if resp.status().is_success() => {
-                let data = resp
-                    .bytes()
-                    .await
-                    .context("Failed to read bytes from response")?;
-                fs::write(file_name, &data).context("Failed to write data to file")?;
+        let existing_len = fs::metadata(&part_file).map(|m| m.len()).unwrap_or(0);
+
+        let mut request = client.get(url);
+        if existing_len > 0 {
+            request = request.header("Range", format!("bytes={}-", existing_len));
+        }
+
+        let host_permit = host_limiter.acquire(url).await?;
+        match request.send().await {
+            Ok(mut resp) if resp.status().is_success() => {
+                let resuming = resp.status() == StatusCode::PARTIAL_CONTENT;
+
+                // Track the expected total size so a resumed-but-incomplete
+                // file isn't mistaken for a completed download.
+                let total_size = if resuming {
+                    resp.headers()
+                        .get(CONTENT_RANGE)
+                        .and_then(|v| v.to_str().ok())
+                        .and_then(|v| v.rsplit('/').next())
+                        .and_then(|v| v.parse::<u64>().ok())
+                } else {
+                    resp.content_length()
+                };
+
+                let mut outfile = if resuming {
+                    tokio::fs::OpenOptions::new()
+                        .append(true)
+                        .open(&part_file)
+                        .await
+                        .context("Failed to open partial file for resume")?
+                } else {
+                    if existing_len > 0 {
+                        eprintln!(
+                            "Server did not honor resume request for {}; restarting from scratch",
+                            url
+                        );
+                    }
+                    tokio::fs::File::create(&part_file)
+                        .await
+                        .context("Failed to create partial file")?
+                };
+
+                // A connection reset mid-stream is the exact "fails partway"
+                // case resuming exists for: treat it like a failed attempt
+                // rather than bailing out, so the next attempt resumes via
+                // Range against the bytes already written to `.part`.
+                let mut stream_interrupted = false;
+                loop {
+                    match resp.chunk().await {
+                        Ok(Some(chunk)) => {
+                            outfile
+                                .write_all(&chunk)
+                                .await
+                                .context("Failed to write chunk to file")?;
+                        }
+                        Ok(None) => break,
+                        Err(e) => {
+                            eprintln!(
+                                "Connection error while downloading {}: {}. Retrying...",
+                                url, e
+                            );
+                            stream_interrupted = true;
+                            break;
+                        }
+                    }
+                }
+                outfile.flush().await.context("Failed to flush output file")?;
+
+                if stream_interrupted {
+                    attempts -= 1;
+                    drop(host_permit);
+                    if attempts > 0 {
+                        backoff_sleep(retry_count - attempts).await;
+                    }
+                    continue;
+                }
+
+                let written = fs::metadata(&part_file)
+                    .context("Failed to stat partial file")?
+                    .len();
+                match total_size {
+                    Some(expected) if written < expected => {
+                        eprintln!(
+                            "Incomplete download for {} ({} of {} bytes). Retrying...",
+                            url, written, expected
+                        );
+                        attempts -= 1;
+                        drop(host_permit);
+                        if attempts > 0 {
+                            backoff_sleep(retry_count - attempts).await;
+                        }
+                        continue;
+                    }
+                    Some(_) => {}
+                    None => {
+                        eprintln!(
+                            "Warning: server did not report a size for {} ({} bytes written); \
+                             cannot confirm the download is complete.",
+                            url, written
+                        );
+                    }
+                }
+
+                fs::rename(&part_file, &file_name)
+                    .context("Failed to rename completed download")?;
+
+                if let Some(expected) = expected_md5 {
+                    let actual = md5_hex(&file_name).await?;
+                    if !actual.eq_ignore_ascii_case(expected) {
+                        eprintln!(
+                            "Checksum mismatch for {} (expected {}, got {}). Retrying...",
+                            file_name, expected, actual
+                        );
+                        fs::remove_file(&file_name).ok();
+                        attempts -= 1;
+                        drop(host_permit);
+                        if attempts > 0 {
+                            backoff_sleep(retry_count - attempts).await;
+                        }
+                        continue;
+                    }
+                }
+
                 return Ok(());
             }
             _ => {
                 eprintln!("Failed to download file: {}. Retrying...", url);
                 attempts -= 1;
+                drop(host_permit);
+                if attempts > 0 {
+                    backoff_sleep(retry_count - attempts).await;
+                }
             }
         }
     }
@@ -93,32 +321,77 @@ async fn download_with_retry(
     ))
 }
 
+/// Map a friendly `--include` value to the NCBI filename suffix it corresponds to.
+fn include_suffix(name: &str) -> &'static str {
+    match name {
+        "genomic" => "_genomic.fna.gz",
+        "protein" => "_protein.faa.gz",
+        "gff" => "_genomic.gff.gz",
+        "assembly-report" => "_assembly_report.txt",
+        other => unreachable!("unexpected --include value: {}", other),
+    }
+}
+
+/// The file types downloaded when `--include` is not given.
+const DEFAULT_SUFFIXES: &[&str] = &["_genomic.fna.gz", "_protein.faa.gz"];
+
 async fn process_accession(
     client: &Client,
     accession: &str,
     location: &PathBuf,
     retry: Option<u32>,
+    suffixes: &[&str],
+    host_limiter: &HostLimiter,
 ) -> Result<()> {
     let retry_count = retry.unwrap_or(3); // Default retry count
 
-    let (base_url, full_name) = fetch_full_filename(client, accession).await?;
+    let (base_url, full_name) = fetch_full_filename(client, accession, host_limiter).await?;
 
-    let suffixes = vec!["_genomic.fna.gz", "_protein.faa.gz"]; //, "_assembly_report.txt"];
     let standalone = vec!["md5checksums.txt"];
 
-    for suffix in suffixes.iter() {
-        let url = format!("{}/{}{}", base_url, full_name, suffix); // Correctly format the URL for each file type
-        let file_name = format!("{}{}", accession, suffix); // Generate file name using the directory name and suffix
-        let path = location.join(&file_name); // Create the full path for the file
-        download_with_retry(client, &url, path, retry_count).await?;
-    }
-
-    // download standalone files (mostly md5checksums.txt)
+    // Try to fetch the checksum manifest first so we can verify the rest of
+    // the downloads against it. A missing or unparsable manifest shouldn't
+    // stop us from downloading the data files, so failures here only log a
+    // warning and leave `checksums` empty (i.e. download without verification).
+    let mut checksums = HashMap::new();
     for filename in standalone {
         let url = format!("{}/{}", base_url, filename);
         let file_name = format!("{}_{}", accession, filename); // Generate file name using the directory name and suffix
         let path = location.join(&file_name); // Create the full path for the file
-        download_with_retry(client, &url, path, retry_count).await?;
+        match download_with_retry(client, &url, path.clone(), retry_count, None, host_limiter)
+            .await
+        {
+            Ok(()) => match parse_checksums(&path) {
+                Ok(parsed) => checksums = parsed,
+                Err(e) => eprintln!(
+                    "Warning: failed to parse checksum manifest for {}: {}. \
+                     Downloading without verification.",
+                    accession, e
+                ),
+            },
+            Err(e) => eprintln!(
+                "Warning: failed to download checksum manifest for {}: {}. \
+                 Downloading without verification.",
+                accession, e
+            ),
+        }
+    }
+
+    for suffix in suffixes.iter() {
+        let url = format!("{}/{}{}", base_url, full_name, suffix); // Correctly format the URL for each file type
+        let file_name = format!("{}{}", accession, suffix); // Generate file name using the directory name and suffix
+        let path = location.join(&file_name); // Create the full path for the file
+        let manifest_name = format!("{}{}", full_name, suffix);
+        let expected_md5 = checksums.get(&manifest_name);
+        download_with_retry(
+            client,
+            &url,
+            path,
+            retry_count,
+            expected_md5.map(|s| s.as_str()),
+            host_limiter,
+        )
+        .await?;
     }
 
     Ok(())
@@ -162,12 +435,44 @@ async fn main() -> Result<()> {
                 .default_value(".")
                 .help("Directory location where files will be downloaded"),
         )
+        .arg(
+            Arg::new("max-concurrent")
+                .short('m')
+                .long("max-concurrent")
+                .takes_value(true)
+                .default_value("3")
+                .help("Maximum number of accessions to download concurrently"),
+        )
+        .arg(
+            Arg::new("include")
+                .long("include")
+                .takes_value(true)
+                .multiple_occurrences(true)
+                .possible_values(["genomic", "protein", "gff", "assembly-report"])
+                .help(
+                    "File type to download; repeat to request multiple \
+                     (default: genomic, protein)",
+                ),
+        )
+        .arg(
+            Arg::new("per-host-limit")
+                .long("per-host-limit")
+                .takes_value(true)
+                .default_value("3")
+                .help("Maximum simultaneous connections to any single download host"),
+        )
         .get_matches();
 
     let input_csv = matches.value_of("input").unwrap();
     let failed_csv = matches.value_of("failed").unwrap();
     let retry_times: u32 = matches.value_of_t("retry-times")?; // Safely parsing the retry times as u32
     let location = matches.value_of("location").unwrap();
+    let max_concurrent: usize = matches.value_of_t("max-concurrent")?;
+    let per_host_limit: usize = matches.value_of_t("per-host-limit")?;
+    let suffixes: Vec<&str> = match matches.values_of("include") {
+        Some(values) => values.map(include_suffix).collect(),
+        None => DEFAULT_SUFFIXES.to_vec(),
+    };
 
     let download_path = PathBuf::from(location);
     if !download_path.exists() {
@@ -195,50 +500,55 @@ async fn main() -> Result<()> {
     let mut failed_writer = Writer::from_path(failed_csv)?;
     failed_writer.write_record(&["accession", "url"])?;
 
-    // Collect accessions into a vector for easier chunking
-    let accessions_vec: Vec<_> = accessions.iter().collect();
-
-    // NCBI rate-limits to 3 requests/second, so process 3 at a time:
-    for chunk in accessions_vec.chunks(3) {
-        let futures = chunk.iter().map(|accession| {
-            let client_ref = &client;
-            let download_path_ref = &download_path;
-            let accession_clone = accession.to_owned();
-            async move {
-                match process_accession(
-                    client_ref,
-                    &accession_clone,
-                    download_path_ref,
-                    Some(retry_times),
-                )
+    // NCBI rate-limits requests, so cap how many accessions we process at once
+    // with a semaphore rather than waiting for fixed-size batches to drain.
+    let semaphore = Arc::new(Semaphore::new(max_concurrent));
+    let host_limiter = Arc::new(HostLimiter::new(per_host_limit));
+
+    let tasks = accessions.into_iter().map(|accession| {
+        let client = client.clone();
+        let download_path = download_path.clone();
+        let semaphore = Arc::clone(&semaphore);
+        let host_limiter = Arc::clone(&host_limiter);
+        let suffixes = suffixes.clone();
+        tokio::spawn(async move {
+            let _permit = semaphore
+                .acquire_owned()
                 .await
-                {
-                    Ok(_) => Ok(accession_clone),
-                    Err(e) => Err((accession_clone, e)),
-                }
+                .expect("semaphore should never be closed");
+            match process_accession(
+                &client,
+                &accession,
+                &download_path,
+                Some(retry_times),
+                &suffixes,
+                &host_limiter,
+            )
+            .await
+            {
+                Ok(_) => Ok(accession),
+                Err(e) => Err((accession, e)),
             }
-        });
-
-        // Wait for all accessions in the current chunk to be processed
-        let results = join_all(futures).await;
-
-        for result in results {
-            match result {
-                Ok(accession) => println!("Successfully processed accession: {}", accession),
-                Err((accession, e)) => {
-                    let err_message = e.to_string();
-                    let parts: Vec<&str> = err_message.split("retries: ").collect();
-                    let failed_url = parts.get(1).unwrap_or(&"Unknown URL").trim();
-
-                    failed_writer.write_record(&[accession, failed_url])?;
-                    eprintln!(
-                        "Failed to process accession: {}. Error: {}",
-                        accession, err_message
-                    );
-                }
+        })
+    });
+
+    let results = join_all(tasks).await;
+
+    for result in results {
+        match result.context("Download task panicked")? {
+            Ok(accession) => println!("Successfully processed accession: {}", accession),
+            Err((accession, e)) => {
+                let err_message = e.to_string();
+                let parts: Vec<&str> = err_message.split("retries: ").collect();
+                let failed_url = parts.get(1).unwrap_or(&"Unknown URL").trim();
+
+                failed_writer.write_record(&[accession, failed_url])?;
+                eprintln!(
+                    "Failed to process accession: {}. Error: {}",
+                    accession, err_message
+                );
             }
         }
-
     }
 
     Ok(())